@@ -24,7 +24,7 @@ extern crate alloc;
 #[cfg(feature = "std")]
 use serde::Serialize;
 use parity_codec::{Encode, Decode};
-use sr_primitives::{ConsensusEngineId, traits::{DigestFor, NumberFor}};
+use sr_primitives::{ConsensusEngineId, generic::DigestItem, traits::{Block as BlockT, DigestFor, NumberFor}};
 use client::decl_runtime_apis;
 use rstd::vec::Vec;
 
@@ -46,6 +46,67 @@ pub const GRANDPA_ENGINE_ID: ConsensusEngineId = *b"FRNK";
 /// The weight of an authority.
 pub type AuthorityWeight = u64;
 
+/// A list of GRANDPA authorities with associated weights.
+pub type AuthorityList = Vec<(AuthorityId, AuthorityWeight)>;
+
+/// A version of `AuthorityList` with a stable, forward-compatible encoding.
+///
+/// This is the representation that should be used when persisting the
+/// authority list to storage or sending it on the wire, so that it can
+/// evolve independently of the runtime's `grandpa_authorities` API.
+#[cfg_attr(feature = "std", derive(Debug, Serialize))]
+#[derive(Clone, Eq, PartialEq, Encode, Decode)]
+pub enum VersionedAuthorityList {
+	/// The initial authority list version.
+	#[codec(index = "1")]
+	V1(AuthorityList),
+}
+
+impl From<AuthorityList> for VersionedAuthorityList {
+	fn from(list: AuthorityList) -> Self {
+		VersionedAuthorityList::V1(list)
+	}
+}
+
+impl From<VersionedAuthorityList> for AuthorityList {
+	fn from(versioned: VersionedAuthorityList) -> Self {
+		match versioned {
+			VersionedAuthorityList::V1(list) => list,
+		}
+	}
+}
+
+#[cfg(test)]
+mod versioned_authority_list_tests {
+	use super::*;
+
+	fn authority_list() -> AuthorityList {
+		vec![(AuthorityId::default(), 1), (AuthorityId::default(), 2)]
+	}
+
+	#[test]
+	fn converts_to_and_from_authority_list() {
+		let list = authority_list();
+
+		let versioned: VersionedAuthorityList = list.clone().into();
+		assert_eq!(versioned, VersionedAuthorityList::V1(list.clone()));
+
+		let roundtripped: AuthorityList = versioned.into();
+		assert_eq!(roundtripped, list);
+	}
+
+	#[test]
+	fn encode_decode_roundtrips_and_is_prefixed_with_the_version() {
+		let versioned: VersionedAuthorityList = authority_list().into();
+
+		let encoded = versioned.encode();
+		assert_eq!(encoded[0], 1);
+
+		let decoded = VersionedAuthorityList::decode(&mut &encoded[..]);
+		assert_eq!(decoded, Some(versioned));
+	}
+}
+
 /// A scheduled change of authority set.
 #[cfg_attr(feature = "std", derive(Debug, Serialize))]
 #[derive(Clone, Eq, PartialEq, Encode, Decode)]
@@ -56,11 +117,324 @@ pub struct ScheduledChange<N> {
 	pub delay: N,
 }
 
+/// Fixtures shared by this crate's test modules.
+#[cfg(test)]
+mod test_helpers {
+	use super::ScheduledChange;
+
+	/// A `ScheduledChange<u64>` with empty `next_authorities`, for tests that
+	/// only care about the delay.
+	pub fn change(delay: u64) -> ScheduledChange<u64> {
+		ScheduledChange { next_authorities: vec![], delay }
+	}
+}
+
+/// A set of pending authority set changes, each keyed by the `(hash, number)`
+/// of the block that signaled it.
+///
+/// This supports the voting rule "if block B signals a change that takes
+/// effect at block NUM(B)+delay, only vote on chains of length NUM(B)+delay
+/// that contain B" by tracking, for an arbitrary set of forks, the lowest
+/// effective block number that has not yet been applied.
+///
+/// Entries are kept sorted by effective block number, ascending, so the
+/// earliest pending change is always at the front of the set.
+#[cfg_attr(feature = "std", derive(Debug, Serialize))]
+#[derive(Clone, Eq, PartialEq, Encode, Decode)]
+pub struct PendingChangeSet<H, N> {
+	signals: Vec<(H, N, ScheduledChange<N>)>,
+}
+
+impl<H, N> Default for PendingChangeSet<H, N> {
+	fn default() -> Self {
+		PendingChangeSet { signals: Vec::new() }
+	}
+}
+
+impl<H, N> PendingChangeSet<H, N>
+where
+	H: Eq + Clone,
+	N: Ord + Clone + core::ops::Add<Output = N>,
+{
+	/// Note a change signaled by the block with the given `hash` and `number`.
+	pub fn insert(&mut self, hash: H, number: N, change: ScheduledChange<N>) {
+		let effective_number = number.clone() + change.delay.clone();
+		let pos = self.signals.iter()
+			.position(|(_, number, change)| number.clone() + change.delay.clone() > effective_number)
+			.unwrap_or_else(|| self.signals.len());
+
+		self.signals.insert(pos, (hash, number, change));
+	}
+
+	/// The earliest block number at which a still-pending change in this set
+	/// takes effect, if any.
+	pub fn enacts_at(&self) -> Option<N> {
+		self.signals.first().map(|(_, number, change)| number.clone() + change.delay.clone())
+	}
+
+	/// The maximum block number that's safe to vote on for the fork described
+	/// by `best` (the best block known on that fork) and `is_descendent_of`,
+	/// without voting past a pending change's effective block. Returns `None`
+	/// if no pending change in this set affects the given fork.
+	pub fn is_descendent_safe(
+		&self,
+		best: (H, N),
+		is_descendent_of: impl Fn(&H, &H) -> bool,
+	) -> Option<N> {
+		self.signals.iter()
+			.filter(|(hash, number, _)| {
+				*number <= best.1 && (*hash == best.0 || is_descendent_of(hash, &best.0))
+			})
+			.map(|(_, number, change)| number.clone() + change.delay.clone())
+			.min()
+	}
+
+	/// Forget all changes that must already have been applied by the time the
+	/// chain has reached `number`, i.e. whose effective block number is less
+	/// than or equal to it.
+	pub fn prune(&mut self, number: N) {
+		self.signals.retain(|(_, signal_number, change)| {
+			signal_number.clone() + change.delay.clone() > number
+		});
+	}
+}
+
+#[cfg(test)]
+mod pending_change_set_tests {
+	use super::*;
+	use super::test_helpers::change;
+
+	// A small two-fork chain used by the `is_descendent_safe` tests:
+	//
+	//           1 -> 2 -> 3 -> 4   (main fork)
+	//   0 (genesis)
+	//           10 -> 11           (other fork)
+	fn is_descendent_of(a: &u64, b: &u64) -> bool {
+		match (*a, *b) {
+			(0, _) => true,
+			(1, 1) | (1, 2) | (1, 3) | (1, 4) => true,
+			(2, 2) | (2, 3) | (2, 4) => true,
+			(3, 3) | (3, 4) => true,
+			(4, 4) => true,
+			(10, 10) | (10, 11) => true,
+			(11, 11) => true,
+			_ => false,
+		}
+	}
+
+	#[test]
+	fn insert_keeps_signals_sorted_by_effective_height() {
+		let mut set = PendingChangeSet::<u64, u64>::default();
+
+		// signaled at block 10 with a delay of 5: effective height 15.
+		set.insert(10, 10, change(5));
+		// signaled at block 1 with a delay of 1: effective height 2, should end
+		// up ahead of the first signal despite being inserted afterwards.
+		set.insert(1, 1, change(1));
+
+		assert_eq!(set.signals[0].1, 1);
+		assert_eq!(set.signals[1].1, 10);
+		assert_eq!(set.enacts_at(), Some(2));
+	}
+
+	#[test]
+	fn enacts_at_on_empty_set_is_none() {
+		let set = PendingChangeSet::<u64, u64>::default();
+		assert_eq!(set.enacts_at(), None);
+	}
+
+	#[test]
+	fn is_descendent_safe_returns_nearest_boundary_on_affected_fork() {
+		let mut set = PendingChangeSet::<u64, u64>::default();
+
+		// signaled at block 1, effective height 1 + 2 = 3.
+		set.insert(1, 1, change(2));
+		// signaled at block 2, effective height 2 + 5 = 7.
+		set.insert(2, 2, change(5));
+
+		assert_eq!(set.is_descendent_safe((4, 4), is_descendent_of), Some(3));
+	}
+
+	#[test]
+	fn is_descendent_safe_is_none_on_fork_without_signals() {
+		let mut set = PendingChangeSet::<u64, u64>::default();
+		set.insert(1, 1, change(2));
+		set.insert(2, 2, change(5));
+
+		assert_eq!(set.is_descendent_safe((11, 11), is_descendent_of), None);
+	}
+
+	#[test]
+	fn prune_removes_only_entries_at_or_below_the_given_number() {
+		let mut set = PendingChangeSet::<u64, u64>::default();
+
+		// effective heights 3 and 7.
+		set.insert(1, 1, change(2));
+		set.insert(2, 2, change(5));
+
+		set.prune(3);
+		assert_eq!(set.enacts_at(), Some(7));
+
+		set.prune(7);
+		assert_eq!(set.enacts_at(), None);
+	}
+}
+
 /// WASM function call to check for pending changes.
 pub const PENDING_CHANGE_CALL: &str = "grandpa_pending_change";
 /// WASM function call to get current GRANDPA authorities.
 pub const AUTHORITIES_CALL: &str = "grandpa_authorities";
 
+/// A consensus log item for GRANDPA, encoded as the associated data of a
+/// `DigestItem::Consensus(GRANDPA_ENGINE_ID, _)` entry in a block's digest.
+///
+/// Having a typed, self-contained log format lets clients decode signals
+/// directly from a block's digest, without having to call into the runtime.
+#[cfg_attr(feature = "std", derive(Debug, Serialize))]
+#[derive(Clone, Eq, PartialEq, Encode, Decode)]
+pub enum ConsensusLog<N> {
+	/// Schedule an authority set change.
+	///
+	/// The earliest digest of this type in a single block will be honored,
+	/// subsequent ones will be ignored.
+	#[codec(index = "1")]
+	ScheduledChange(ScheduledChange<N>),
+	/// Force an authority set change.
+	///
+	/// The earliest digest of this type in a single block will be honored,
+	/// subsequent ones will be ignored.
+	#[codec(index = "2")]
+	ForcedChange(N, ScheduledChange<N>),
+	/// Note that the given authority has misbehaved in a way that requires
+	/// removing them from the authority set.
+	#[codec(index = "3")]
+	OnDisabled(AuthorityWeight),
+	/// A signal to pause the current authority set after the given delay.
+	#[codec(index = "4")]
+	Pause(N),
+	/// A signal to resume the current authority set after the given delay.
+	#[codec(index = "5")]
+	Resume(N),
+}
+
+/// Reads a digest for GRANDPA consensus log items, skipping items that belong
+/// to other consensus engines or that fail to decode as a `ConsensusLog`.
+pub struct GrandpaConsensusLogReader<N>(rstd::marker::PhantomData<N>);
+
+impl<N: Decode> GrandpaConsensusLogReader<N> {
+	/// Find the earliest `ScheduledChange` signaled in the given digest, if any.
+	pub fn find_scheduled_change<B: BlockT>(
+		digest: &DigestFor<B>,
+	) -> Option<ScheduledChange<N>> {
+		Self::find(digest, |log| match log {
+			ConsensusLog::ScheduledChange(change) => Some(change),
+			_ => None,
+		})
+	}
+
+	/// Find the earliest `ForcedChange` signaled in the given digest, if any.
+	pub fn find_forced_change<B: BlockT>(
+		digest: &DigestFor<B>,
+	) -> Option<(N, ScheduledChange<N>)> {
+		Self::find(digest, |log| match log {
+			ConsensusLog::ForcedChange(median, change) => Some((median, change)),
+			_ => None,
+		})
+	}
+
+	/// Iterate the GRANDPA consensus digest items in `digest`, decoding each as
+	/// a `ConsensusLog` and returning the first one for which `filter` yields
+	/// `Some`. Items belonging to other engines, or that fail to decode, are
+	/// skipped.
+	fn find<B: BlockT, T>(
+		digest: &DigestFor<B>,
+		filter: impl Fn(ConsensusLog<N>) -> Option<T>,
+	) -> Option<T> {
+		digest.logs().iter()
+			.filter_map(|log| match log {
+				DigestItem::Consensus(id, data) if id == &GRANDPA_ENGINE_ID =>
+					ConsensusLog::decode(&mut &data[..]),
+				_ => None,
+			})
+			.filter_map(filter)
+			.next()
+	}
+}
+
+#[cfg(test)]
+mod consensus_log_reader_tests {
+	use super::*;
+	use super::test_helpers::change;
+	use sr_primitives::testing::Block as TestBlock;
+
+	type Hash = <TestBlock as BlockT>::Hash;
+
+	fn digest(logs: Vec<DigestItem<Hash>>) -> DigestFor<TestBlock> {
+		DigestFor::<TestBlock> { logs }
+	}
+
+	fn encode_log(log: ConsensusLog<u64>) -> DigestItem<Hash> {
+		DigestItem::Consensus(GRANDPA_ENGINE_ID, log.encode())
+	}
+
+	#[test]
+	fn finds_scheduled_change_skipping_other_engines_and_undecodable_items() {
+		let wanted = change(10);
+		let other_engine = DigestItem::Consensus(
+			*b"OTHR",
+			ConsensusLog::ScheduledChange(wanted.clone()).encode(),
+		);
+		let garbage = DigestItem::Consensus(GRANDPA_ENGINE_ID, vec![255]);
+		let good = encode_log(ConsensusLog::ScheduledChange(wanted.clone()));
+
+		let digest = digest(vec![other_engine, garbage, good]);
+
+		assert_eq!(
+			GrandpaConsensusLogReader::<u64>::find_scheduled_change::<TestBlock>(&digest),
+			Some(wanted),
+		);
+	}
+
+	#[test]
+	fn finds_forced_change() {
+		let wanted = change(10);
+		let digest = digest(vec![encode_log(ConsensusLog::ForcedChange(5, wanted.clone()))]);
+
+		assert_eq!(
+			GrandpaConsensusLogReader::<u64>::find_forced_change::<TestBlock>(&digest),
+			Some((5, wanted)),
+		);
+	}
+
+	#[test]
+	fn returns_first_match_and_ignores_later_ones() {
+		let first = change(1);
+		let second = change(2);
+		let digest = digest(vec![
+			encode_log(ConsensusLog::ScheduledChange(first.clone())),
+			encode_log(ConsensusLog::ScheduledChange(second)),
+		]);
+
+		assert_eq!(
+			GrandpaConsensusLogReader::<u64>::find_scheduled_change::<TestBlock>(&digest),
+			Some(first),
+		);
+	}
+
+	#[test]
+	fn returns_none_when_nothing_matches() {
+		let digest = digest(vec![
+			DigestItem::Consensus(*b"OTHR", vec![1, 2, 3]),
+			DigestItem::Consensus(GRANDPA_ENGINE_ID, vec![255]),
+		]);
+
+		assert_eq!(
+			GrandpaConsensusLogReader::<u64>::find_scheduled_change::<TestBlock>(&digest),
+			None,
+		);
+	}
+}
+
 pub type PrevoteEquivocation<Block, Hash> =
 	Equivocation<AuthorityId, Prevote<Hash, NumberFor<Block>>, AuthoritySignature>;
 pub type PrecommitEquivocation<Block, Hash> =
@@ -76,7 +450,7 @@ decl_runtime_apis! {
 	/// applied in the runtime after those N blocks have passed.
 	///
 	/// The consensus protocol will coordinate the handoff externally.
-	#[api_version(2)]
+	#[api_version(3)]
 	pub trait GrandpaApi {
 		/// Check a digest for pending changes.
 		/// Return `None` if there are no pending changes.
@@ -121,17 +495,61 @@ decl_runtime_apis! {
 		/// When called at block B, it will return the set of authorities that should be
 		/// used to finalize descendants of this block (B+1, B+2, ...). The block B itself
 		/// is finalized by the authorities from block B-1.
-		fn grandpa_authorities() -> Vec<(AuthorityId, AuthorityWeight)>;
-		
+		fn grandpa_authorities() -> AuthorityList;
+
 		/// Construct a call to report the prevote equivocation.
 		fn construct_prevote_equivocation_report_call(
 			proof: GrandpaEquivocationProof<PrevoteEquivocation<Block, Block::Hash>>
 		) -> Vec<u8>;
-		
+
 		/// Construct a call to report the precommit equivocation.
 		fn construct_precommit_equivocation_report_call(
 			proof: GrandpaEquivocationProof<PrecommitEquivocation<Block, Block::Hash>>
 		) -> Vec<u8>;
+
+		/// Get current GRANDPA authority set id.
+		///
+		/// Added in version 3.
+		fn current_set_id() -> u64;
+
+		/// Generates a proof that the given authority was part of the GRANDPA
+		/// authority set at the given `set_id`.
+		///
+		/// Added in version 3.
+		///
+		/// Returns `None` if the authority is not part of the set, or if the
+		/// proof could not be generated (e.g. historical data is not available).
+		fn generate_key_ownership_proof(
+			set_id: u64,
+			authority_id: AuthorityId,
+		) -> Option<OpaqueKeyOwnershipProof>;
+
+		/// Submits an unsigned extrinsic to report the prevote equivocation. The
+		/// caller must provide the `equivocation_proof` along with a
+		/// `key_owner_proof` (generated using `generate_key_ownership_proof`)
+		/// binding the offending authority's membership in the set at the time
+		/// of the equivocation, so the runtime can verify it before slashing.
+		///
+		/// Added in version 3.
+		///
+		/// The extrinsic will be unsigned and should only be accepted for local
+		/// authorship (not to be broadcast to the network). This method returns
+		/// `None` when creation of the extrinsic fails, e.g. if equivocation
+		/// reporting is disabled for the given runtime.
+		fn submit_report_prevote_equivocation_unsigned_extrinsic(
+			equivocation_proof: GrandpaEquivocationProof<PrevoteEquivocation<Block, Block::Hash>>,
+			key_owner_proof: OpaqueKeyOwnershipProof,
+		) -> Option<()>;
+
+		/// Submits an unsigned extrinsic to report the precommit equivocation.
+		/// See `submit_report_prevote_equivocation_unsigned_extrinsic` for
+		/// details.
+		///
+		/// Added in version 3.
+		fn submit_report_precommit_equivocation_unsigned_extrinsic(
+			equivocation_proof: GrandpaEquivocationProof<PrecommitEquivocation<Block, Block::Hash>>,
+			key_owner_proof: OpaqueKeyOwnershipProof,
+		) -> Option<()>;
 	}
 }
 
@@ -141,3 +559,46 @@ pub struct GrandpaEquivocationProof<E> {
 	pub round: u64,
 	pub equivocation: E,
 }
+
+/// Opaque type used to represent a key ownership proof at the runtime API
+/// boundary. The inner value is not accessible outside of the runtime, but
+/// its concrete representation can change freely between runtime versions
+/// without affecting the API signature.
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Clone, Eq, PartialEq, Encode, Decode)]
+pub struct OpaqueKeyOwnershipProof(Vec<u8>);
+
+impl OpaqueKeyOwnershipProof {
+	/// Create a new `OpaqueKeyOwnershipProof` using the given encoded
+	/// representation.
+	pub fn new(inner: Vec<u8>) -> OpaqueKeyOwnershipProof {
+		OpaqueKeyOwnershipProof(inner)
+	}
+
+	/// Try to decode this `OpaqueKeyOwnershipProof` into the given concrete
+	/// key ownership proof type.
+	pub fn decode<T: Decode>(self) -> Option<T> {
+		Decode::decode(&mut &self.0[..])
+	}
+}
+
+#[cfg(test)]
+mod opaque_key_ownership_proof_tests {
+	use super::*;
+
+	#[test]
+	fn decode_roundtrips_through_new() {
+		let proof: (AuthorityId, u64) = (AuthorityId::default(), 42);
+
+		let opaque = OpaqueKeyOwnershipProof::new(proof.encode());
+
+		assert_eq!(opaque.decode::<(AuthorityId, u64)>(), Some(proof));
+	}
+
+	#[test]
+	fn decode_returns_none_for_garbage_bytes() {
+		let opaque = OpaqueKeyOwnershipProof::new(vec![255]);
+
+		assert_eq!(opaque.decode::<(AuthorityId, u64)>(), None);
+	}
+}